@@ -9,4 +9,13 @@ pub enum Error {
     DoesNotExist,
     #[error("External `{0}`")]
     External(String),
+    /// An output artifact could not be encoded in the requested
+    /// format.
+    #[error("Could not encode output: `{0}`")]
+    EncodingError(String),
+    /// The source image is in a format intarsia does not know how
+    /// to decode (as opposed to a file that claims a supported
+    /// format but is corrupt).
+    #[error("Unsupported source image format: `{0}`")]
+    UnsupportedFormat(String),
 }
@@ -2,24 +2,35 @@ extern crate clap_verbosity_flag;
 extern crate dirs;
 extern crate image;
 extern crate imageproc;
-extern crate palette_extract;
 extern crate plotters;
 extern crate strum_macros;
 mod err;
+mod processor;
 mod utils;
 
 use crate::err::Error;
-use crate::utils::{add_grid_to_image, colour2rgb, plot_image_with_axes, set_closest_colour};
+use crate::processor::{parse_pipeline, run_pipeline};
+use crate::utils::{
+    add_symbol_chart, count_stitches, dither_floyd_steinberg, generate_instructions,
+    plot_image_with_axes, read_image, render_ascii_preview, render_legend_image, resize_with_mode,
+    save_chart, snap_to_palette_parallel, write_instructions_json, write_instructions_text,
+    write_legend_text, Artifact, LegendEntry, OutputFormat, QuantizeAlgorithm, ResizeMode,
+    TransformOutputs, SYMBOL_COUNT,
+};
 use image::imageops::blur;
 use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
 use image::DynamicImage;
+use image::Pixel;
 use image::Rgb;
-use palette_extract::{get_palette_with_options, MaxColors, PixelEncoding, PixelFilter, Quality};
+use rayon::ThreadPoolBuilder;
 use std::process;
 use std::process::Command;
 use std::str::FromStr;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use structopt::StructOpt;
 use strum_macros::EnumString;
 
@@ -45,11 +56,11 @@ struct Image {
     image: DynamicImage,
 }
 
+/// The written, row-by-row instructions generated alongside a
+/// project's chart, and where they're stored on disk.
 struct Instructions {
-    /// This is the text with the instructions.
-    /// TODO: Add functionality so that these instructions can
-    /// be read from a given point within the project.
-    _text: String,
+    text_path: PathBuf,
+    json_path: PathBuf,
 }
 
 /// Represents a project instance. This holds information about
@@ -63,8 +74,12 @@ struct Project {
     original_image: Option<Image>,
     /// Processed image.
     processed_image: Option<Image>,
-    /// The instructions for this crochet project.
-    _instructions: Option<Instructions>,
+    /// The written instructions for this crochet project, if they've
+    /// been generated yet.
+    instructions: Option<Instructions>,
+    /// The actual stitch grid (columns, rows) of the processed
+    /// image, as determined by the `ResizeMode` used to create it.
+    grid_size: (u32, u32),
 }
 
 impl Project {
@@ -96,7 +111,8 @@ impl Project {
             path,
             original_image: None,
             processed_image: None,
-            _instructions: None,
+            instructions: None,
+            grid_size: (0, 0),
         })
     }
 
@@ -147,11 +163,22 @@ impl Project {
             path,
             original_image,
             processed_image,
-            _instructions: None,
+            instructions: None,
+            // The grid size used to produce the processed image isn't
+            // read back here; `regenerate_instructions` recovers it
+            // from `legend.txt`.
+            grid_size: (0, 0),
         })
     }
 
-    fn show(self, image_type: ImageType) -> Result<(), Error> {
+    fn show(
+        self,
+        image_type: ImageType,
+        type_label: &str,
+        ascii: bool,
+        columns: u32,
+        json: bool,
+    ) -> Result<(), Error> {
         let image_file: PathBuf;
         match image_type {
             ImageType::Original => {
@@ -172,11 +199,28 @@ impl Project {
                 }
             }
         }
-        Command::new("open")
-            .arg(image_file)
-            .output()
-            .map_err(|e| Error::External(e.to_string()))?;
-        Ok(())
+        if json {
+            let (width, height) =
+                image::image_dimensions(&image_file).map_err(|e| Error::External(e.to_string()))?;
+            println!(
+                "{{\"name\":\"{}\",\"type\":\"{}\",\"path\":\"{}\",\"width\":{},\"height\":{}}}",
+                json_escape(&self.name),
+                json_escape(type_label),
+                json_escape(image_file.to_str().unwrap_or("")),
+                width,
+                height
+            );
+            return Ok(());
+        }
+        if ascii {
+            let image = ImageReader::open(&image_file)
+                .map_err(|e| Error::External(e.to_string()))?
+                .decode()
+                .map_err(|e| Error::External(e.to_string()))?;
+            print!("{}", render_ascii_preview(&image, columns));
+            return Ok(());
+        }
+        open_in_viewer(&image_file)
     }
 
     /// This function removes the current project, if it indeed
@@ -190,10 +234,7 @@ impl Project {
     /// Reads a new image, given a file-path string, and saves
     /// it in the project folder under the name `original.png`.
     fn read_image(&mut self, image: String) -> Result<(), Error> {
-        let image = ImageReader::open(&image)
-            .map_err(|e| Error::External(e.to_string()))?
-            .decode()
-            .map_err(|e| Error::External(e.to_string()))?;
+        let image = read_image(Path::new(&image))?;
         let mut path: PathBuf = self.path.clone();
         path.push("original.png");
         image
@@ -209,8 +250,25 @@ impl Project {
 
     // Reduces the number of colours (i.e. "quantizes") an image
     // with the number of desired colours and image dimensions
-    // as parameters.
-    fn reduce_colours(&self, image: DynamicImage, colours: u8) -> Result<DynamicImage, Error> {
+    // as parameters. The palette is chosen via `algorithm` (median-
+    // cut by default: split the box of pixels with the widest
+    // channel spread at its median, repeatedly, until there are
+    // enough boxes; or k-means in CIELAB space, which is more
+    // perceptually accurate but costs several passes over the pixel
+    // data). Each pixel is then either snapped to its nearest
+    // palette colour or, if `dither` is set, Floyd-Steinberg
+    // dithered onto it so banding doesn't show up in tightly
+    // colour-constrained charts; nearest-colour assignment is
+    // embarrassingly parallel, so it runs on a rayon pool sized to
+    // `threads`.
+    fn reduce_colours(
+        &self,
+        image: DynamicImage,
+        colours: u8,
+        algorithm: QuantizeAlgorithm,
+        dither: bool,
+        threads: usize,
+    ) -> Result<(DynamicImage, Vec<Rgb<u8>>), Error> {
         let mut input_path: PathBuf = self.path.clone();
         input_path.push("quantization_input.png");
         image
@@ -220,19 +278,22 @@ impl Project {
             .map_err(|e| Error::External(e.to_string()))?
             .decode()
             .map_err(|e| Error::External(e.to_string()))?;
-        let image_bytes = image.as_bytes();
-        let colour_palette = get_palette_with_options(
-            &image_bytes,
-            PixelEncoding::Rgba,
-            Quality::default(),
-            MaxColors::default(),
-            PixelFilter::None,
-        );
-        let palette: Vec<Rgb<u8>> = colour_palette.iter().map(|x| colour2rgb(*x)).collect();
         let mut quantized_image = image.to_rgb8();
-        for pixel in quantized_image.enumerate_pixels_mut() {
-            set_closest_colour(pixel, &palette[0..(colours as usize)]);
-        }
+        let palette = algorithm.build_palette(
+            &quantized_image.pixels().copied().collect::<Vec<_>>(),
+            colours as usize,
+        );
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| Error::External(e.to_string()))?;
+        pool.install(|| {
+            if dither {
+                dither_floyd_steinberg(&mut quantized_image, &palette);
+            } else {
+                snap_to_palette_parallel(&mut quantized_image, &palette);
+            }
+        });
         let mut quantized_path: PathBuf = self.path.clone();
         quantized_path.push("quantized.png");
         quantized_image
@@ -244,62 +305,424 @@ impl Project {
             .map_err(|e| Error::External(e.to_string()))?
             .decode()
             .map_err(|e| Error::External(e.to_string()))?;
-        Ok(output_image)
+        Ok((output_image, palette))
     }
 
     /// This function has most of the functionality. It
     /// transforms the input image by doing the following:
-    /// 1. Resizing down the original image
-    /// 2. Resizing the image back up to its original dimensions
+    /// 1. Resizing down the original image, according to `mode`.
+    /// 2. Resizing the image back up to its original dimensions.
     /// 3. Reducing the number of colours by calling the
     ///     `reduce_colours` function.
-    /// 4. Adding a grid to the image by calling the
-    ///     `add_grid_to_image` function.
-    /// Finally, it stores the output in the project path.
+    /// 4. Stamping a grid and symbol chart over the quantized image
+    ///     by calling the `add_symbol_chart` function, and writing
+    ///     the colour legend to `legend.png`/`legend.txt`.
+    /// Finally, it stores the output in the project path (the
+    /// processed chart in each of `formats`), records the actual stitch grid
+    /// on `self.grid_size`, and returns a `TransformOutputs` listing
+    /// every artifact produced, instead of making the caller guess
+    /// filenames.
     fn transform_image(
         &mut self,
-        output_width: u32,
-        output_height: u32,
+        mode: ResizeMode,
         colours: u8,
-    ) -> Result<(), Error> {
+        algorithm: QuantizeAlgorithm,
+        formats: &[OutputFormat],
+        dither: bool,
+        threads: usize,
+    ) -> Result<TransformOutputs, Error> {
         let mut image = self.original_image.as_ref().unwrap().image.clone();
         let width = image.width();
         let height = image.height();
         image = DynamicImage::ImageRgba8(blur(&image, 3.0));
-        image = image.resize_exact(output_width, output_height, FilterType::Nearest);
+        let (resized, grid_width, grid_height) = resize_with_mode(&image, mode);
+        image = resized;
+        self.grid_size = (grid_width, grid_height);
         let mut path: PathBuf = self.path.clone();
         path.push("resized_down.png");
         image
             .save(&path)
             .map_err(|e| Error::External(e.to_string()))?;
+        let resized_down = Artifact::new(path, image.width(), image.height());
         image = image.resize_exact(width, height, FilterType::Nearest);
         let mut path: PathBuf = self.path.clone();
         path.push("resized_up.png");
         image
             .save(&path)
             .map_err(|e| Error::External(e.to_string()))?;
-        image = self
-            .reduce_colours(image, colours)
+        let (reduced_image, palette) = self
+            .reduce_colours(image, colours, algorithm, dither, threads)
             .map_err(|e| Error::External(e.to_string()))?;
-        let mut path: PathBuf = self.path.clone();
-        path.push("processed.png");
-        add_grid_to_image(&mut image, output_width, output_height);
-        image
-            .save(&path)
+        image = reduced_image;
+        let quantized = Artifact::new(
+            self.path.join("quantized.png"),
+            image.width(),
+            image.height(),
+        );
+        // Stitch counts and instructions must be sampled before
+        // `add_symbol_chart` stamps a symbol over every cell center;
+        // after that, the cell center pixel is the symbol's ink
+        // colour, not the stitch colour.
+        let stitch_counts = count_stitches(&image, grid_width, grid_height, &palette);
+        let quantized_chart = image.clone();
+        add_symbol_chart(&mut image, grid_width, grid_height, &palette);
+        let mut processed_path_stub: PathBuf = self.path.clone();
+        processed_path_stub.push("processed");
+        let path = save_chart(&image, grid_width, grid_height, &processed_path_stub, formats[0])?;
+        let processed = Artifact::new(path.clone(), image.width(), image.height());
+        let extra_formats = formats[1..]
+            .iter()
+            .map(|format| {
+                let extra_path =
+                    save_chart(&image, grid_width, grid_height, &processed_path_stub, *format)?;
+                Ok(Artifact::new(extra_path, image.width(), image.height()))
+            })
+            .collect::<Result<Vec<Artifact>, Error>>()?;
+        // `plot_image_with_axes` only knows how to load a PNG back
+        // in to overlay axes on it; for a non-PNG primary format
+        // there's no PNG at `path` to load, so skip the overlay
+        // rather than crash trying to decode a WebP/SVG as PNG.
+        if formats[0] == OutputFormat::Png {
+            plot_image_with_axes(
+                path.to_str().unwrap(),
+                path.to_str().unwrap(),
+                grid_width,
+                grid_height,
+            )
+            .map_err(|e| Error::External(e.to_string()))?;
+        }
+        let legend_entries: Vec<LegendEntry> = palette
+            .iter()
+            .zip(stitch_counts.iter())
+            .enumerate()
+            .map(|(i, (colour, count))| LegendEntry {
+                colour: *colour,
+                symbol_index: i,
+                stitch_count: *count,
+            })
+            .collect();
+        let legend_image = render_legend_image(&legend_entries);
+        let mut legend_image_path: PathBuf = self.path.clone();
+        legend_image_path.push("legend.png");
+        legend_image
+            .save(&legend_image_path)
             .map_err(|e| Error::External(e.to_string()))?;
-        plot_image_with_axes(
-            self.name.as_str(),
-            path.to_str().unwrap(),
-            path.to_str().unwrap(),
-        )
-        .unwrap();
+        let legend_image_artifact = Artifact::new(
+            legend_image_path,
+            legend_image.width(),
+            legend_image.height(),
+        );
+        let mut legend_text_path: PathBuf = self.path.clone();
+        legend_text_path.push("legend.txt");
+        write_legend_text(&legend_text_path, &legend_entries, grid_width, grid_height)?;
+        self.write_instructions_files(&quantized_chart, grid_width, grid_height, &palette, &legend_entries)?;
         self.processed_image = Some(Image {
             _image_type: ImageType::Processed,
-            path: path,
+            path,
             image,
         });
+        Ok(TransformOutputs {
+            resized_down,
+            quantized,
+            processed,
+            legend_image: legend_image_artifact,
+            legend_text: legend_text_path,
+            stages: Vec::new(),
+            palette,
+            extra_formats,
+        })
+    }
+
+    /// Runs a user-supplied `--pipeline` string (e.g.
+    /// `"blur=3,resize=40x50,quantize=6,grid"`) over the original
+    /// image instead of the fixed blur/resize/quantize/grid
+    /// sequence `transform_image` hard-codes. Intermediate stage
+    /// outputs are stored under a `pipeline/` subfolder of the
+    /// project, one subfolder per stage, instead of the scattered
+    /// `resized_down.png`/`resized_up.png`/`quantized.png` names.
+    fn transform_with_pipeline(
+        &mut self,
+        spec: &str,
+        formats: &[OutputFormat],
+        threads: usize,
+    ) -> Result<TransformOutputs, Error> {
+        let mut stages = parse_pipeline(spec)?;
+        let image = self.original_image.as_ref().unwrap().image.clone();
+        let mut cache_dir: PathBuf = self.path.clone();
+        cache_dir.push("pipeline");
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| Error::External(e.to_string()))?;
+        let (image, quantized_chart, grid_size, palette, stage_artifacts) =
+            pool.install(|| run_pipeline(&mut stages, image, &cache_dir))?;
+        self.grid_size = grid_size;
+
+        let mut processed_path_stub: PathBuf = self.path.clone();
+        processed_path_stub.push("processed");
+        let path = save_chart(&image, grid_size.0, grid_size.1, &processed_path_stub, formats[0])?;
+        let processed = Artifact::new(path.clone(), image.width(), image.height());
+        let extra_formats = formats[1..]
+            .iter()
+            .map(|format| {
+                let extra_path =
+                    save_chart(&image, grid_size.0, grid_size.1, &processed_path_stub, *format)?;
+                Ok(Artifact::new(extra_path, image.width(), image.height()))
+            })
+            .collect::<Result<Vec<Artifact>, Error>>()?;
+        // See the matching guard in `transform_image`: skip the
+        // overlay rather than crash trying to load a non-PNG primary
+        // format back in as PNG.
+        if formats[0] == OutputFormat::Png {
+            plot_image_with_axes(
+                path.to_str().unwrap(),
+                path.to_str().unwrap(),
+                grid_size.0,
+                grid_size.1,
+            )
+            .map_err(|e| Error::External(e.to_string()))?;
+        }
+
+        // Sample stitch colours from `quantized_chart`, not `image`:
+        // for a pipeline ending in `grid`, `image` already has a
+        // symbol stamped over every cell center.
+        let stitch_counts = count_stitches(&quantized_chart, grid_size.0, grid_size.1, &palette);
+        let legend_entries: Vec<LegendEntry> = palette
+            .iter()
+            .zip(stitch_counts.iter())
+            .enumerate()
+            .map(|(i, (colour, count))| LegendEntry {
+                colour: *colour,
+                symbol_index: i,
+                stitch_count: *count,
+            })
+            .collect();
+        let legend_image = render_legend_image(&legend_entries);
+        let mut legend_image_path: PathBuf = self.path.clone();
+        legend_image_path.push("legend.png");
+        legend_image
+            .save(&legend_image_path)
+            .map_err(|e| Error::External(e.to_string()))?;
+        let legend_image_artifact = Artifact::new(
+            legend_image_path,
+            legend_image.width(),
+            legend_image.height(),
+        );
+        let mut legend_text_path: PathBuf = self.path.clone();
+        legend_text_path.push("legend.txt");
+        write_legend_text(&legend_text_path, &legend_entries, grid_size.0, grid_size.1)?;
+        self.write_instructions_files(&quantized_chart, grid_size.0, grid_size.1, &palette, &legend_entries)?;
+
+        self.processed_image = Some(Image {
+            _image_type: ImageType::Processed,
+            path,
+            image,
+        });
+        Ok(TransformOutputs {
+            resized_down: stage_artifacts
+                .first()
+                .cloned()
+                .unwrap_or_else(|| processed.clone()),
+            quantized: stage_artifacts
+                .last()
+                .cloned()
+                .unwrap_or_else(|| processed.clone()),
+            processed,
+            legend_image: legend_image_artifact,
+            legend_text: legend_text_path,
+            stages: stage_artifacts,
+            palette,
+            extra_formats,
+        })
+    }
+
+    /// Writes `instructions.txt`/`instructions.json` for `image`'s
+    /// grid/palette into the project folder, and records them on
+    /// `self.instructions`. Shared by `transform_image` and
+    /// `transform_with_pipeline`, and by `regenerate_instructions` for
+    /// a reloaded project.
+    fn write_instructions_files(
+        &mut self,
+        image: &DynamicImage,
+        grid_width: u32,
+        grid_height: u32,
+        palette: &[Rgb<u8>],
+        legend_entries: &[LegendEntry],
+    ) -> Result<(), Error> {
+        if palette.is_empty() || legend_entries.is_empty() {
+            // A `--pipeline` with no `quantize` stage has no palette
+            // to generate a legend or written instructions from;
+            // leave `self.instructions` unset rather than writing
+            // out instructions for a chart with no stitch colours.
+            self.instructions = None;
+            return Ok(());
+        }
+        let rows = generate_instructions(image, grid_width, grid_height, palette);
+        let mut text_path: PathBuf = self.path.clone();
+        text_path.push("instructions.txt");
+        write_instructions_text(&text_path, &rows, legend_entries)?;
+        let mut json_path: PathBuf = self.path.clone();
+        json_path.push("instructions.json");
+        write_instructions_json(&json_path, &rows, legend_entries)?;
+        self.instructions = Some(Instructions {
+            text_path,
+            json_path,
+        });
         Ok(())
     }
+
+    /// Regenerates this project's written instructions after a
+    /// reload. The stitch grid and palette used to produce
+    /// `processed.png` aren't persisted directly (see `load`), but
+    /// `legend.txt` already records both, so it's parsed back instead
+    /// of duplicating that state in a new file; the stitches
+    /// themselves are re-sampled from `quantized.png`, the chart
+    /// before the grid/symbols were stamped over it.
+    fn regenerate_instructions(&mut self) -> Result<(), Error> {
+        let mut legend_path: PathBuf = self.path.clone();
+        legend_path.push("legend.txt");
+        let (grid_size, legend_entries) = parse_legend_file(&legend_path)?;
+        let mut quantized_path: PathBuf = self.path.clone();
+        quantized_path.push("quantized.png");
+        let image = ImageReader::open(&quantized_path)
+            .map_err(|e| Error::External(e.to_string()))?
+            .decode()
+            .map_err(|e| Error::External(e.to_string()))?;
+        let palette: Vec<Rgb<u8>> = legend_entries.iter().map(|entry| entry.colour).collect();
+        self.grid_size = grid_size;
+        self.write_instructions_files(&image, grid_size.0, grid_size.1, &palette, &legend_entries)
+    }
+}
+
+/// Parses a `legend.txt` written by `write_legend_text` back into the
+/// stitch grid and legend entries it describes, so a reloaded project
+/// can recover state `Project::load` doesn't otherwise persist.
+fn parse_legend_file(path: &Path) -> Result<((u32, u32), Vec<LegendEntry>), Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::External(e.to_string()))?;
+    let mut grid_size = (0u32, 0u32);
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        if let Some(rest) = line.strip_prefix("Grid: ") {
+            if let Some((width, rest)) = rest.split_once(" columns x ") {
+                if let Some(height) = rest.strip_suffix(" rows") {
+                    if let (Ok(width), Ok(height)) = (width.parse(), height.parse()) {
+                        grid_size = (width, height);
+                    }
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("symbol #") {
+            let fields: Vec<&str> = rest.split('|').map(str::trim).collect();
+            if fields.len() != 4 {
+                continue;
+            }
+            let index: usize = match fields[0].parse() {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+            let channels: Vec<u8> = match fields[2]
+                .trim_start_matches("rgb(")
+                .trim_end_matches(')')
+                .split(',')
+                .map(|channel| channel.trim().parse())
+                .collect()
+            {
+                Ok(channels) => channels,
+                Err(_) => continue,
+            };
+            if channels.len() != 3 {
+                continue;
+            }
+            let stitch_count: u32 = fields[3].trim_end_matches(" stitches").parse().unwrap_or(0);
+            entries.push(LegendEntry {
+                colour: Rgb([channels[0], channels[1], channels[2]]),
+                symbol_index: index,
+                stitch_count,
+            });
+        }
+    }
+    Ok((grid_size, entries))
+}
+
+/// Escapes `"` and `\` so `s` can be embedded in a JSON string
+/// literal. Paths and names in this project are expected to be
+/// plain ASCII, so this doesn't attempt full JSON string escaping.
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders an `Artifact` as a `{"path": ..., "width": ..., "height": ...}` object.
+fn artifact_json(artifact: &Artifact) -> String {
+    format!(
+        "{{\"path\":\"{}\",\"width\":{},\"height\":{}}}",
+        json_escape(artifact.path.to_str().unwrap_or("")),
+        artifact.width,
+        artifact.height
+    )
+}
+
+/// Renders a colour as a `#rrggbb` hex string.
+fn hex_colour(colour: &Rgb<u8>) -> String {
+    let channels = colour.channels();
+    format!("#{:02x}{:02x}{:02x}", channels[0], channels[1], channels[2])
+}
+
+/// Opens `path` in the platform's default image viewer: `open` on
+/// macOS, `xdg-open` on Linux, and `cmd /C start` on Windows (which
+/// needs an empty title argument before the path, since `start`
+/// treats its first quoted argument as a window title).
+fn open_in_viewer(path: &Path) -> Result<(), Error> {
+    let result = if cfg!(target_os = "macos") {
+        Command::new("open").arg(path).output()
+    } else if cfg!(target_os = "windows") {
+        Command::new("cmd").args(["/C", "start", ""]).arg(path).output()
+    } else {
+        Command::new("xdg-open").arg(path).output()
+    };
+    result.map_err(|e| Error::External(e.to_string()))?;
+    Ok(())
+}
+
+/// The resize strategy requested on the command line. Combined with
+/// `width`/`height` to build a `utils::ResizeMode`.
+#[derive(EnumString)]
+enum ResizeModeArg {
+    #[strum(serialize = "exact")]
+    Exact,
+    #[strum(serialize = "fit-width")]
+    FitWidth,
+    #[strum(serialize = "fit-height")]
+    FitHeight,
+    #[strum(serialize = "fit")]
+    Fit,
+    #[strum(serialize = "fill")]
+    Fill,
+}
+
+impl ResizeModeArg {
+    /// Builds the `ResizeMode` this argument describes, validating
+    /// that `width`/`height` were given where the mode needs them.
+    fn into_resize_mode(self, width: Option<u32>, height: Option<u32>) -> Result<ResizeMode, Error> {
+        match self {
+            ResizeModeArg::Exact => Ok(ResizeMode::Exact(
+                width.ok_or_else(|| Error::External("`--width` is required for `exact`".to_string()))?,
+                height.ok_or_else(|| Error::External("`--height` is required for `exact`".to_string()))?,
+            )),
+            ResizeModeArg::FitWidth => Ok(ResizeMode::FitWidth(width.ok_or_else(|| {
+                Error::External("`--width` is required for `fit-width`".to_string())
+            })?)),
+            ResizeModeArg::FitHeight => Ok(ResizeMode::FitHeight(height.ok_or_else(|| {
+                Error::External("`--height` is required for `fit-height`".to_string())
+            })?)),
+            ResizeModeArg::Fit => Ok(ResizeMode::Fit(
+                width.ok_or_else(|| Error::External("`--width` is required for `fit`".to_string()))?,
+                height.ok_or_else(|| Error::External("`--height` is required for `fit`".to_string()))?,
+            )),
+            ResizeModeArg::Fill => Ok(ResizeMode::Fill(
+                width.ok_or_else(|| Error::External("`--width` is required for `fill`".to_string()))?,
+                height.ok_or_else(|| Error::External("`--height` is required for `fill`".to_string()))?,
+            )),
+        }
+    }
 }
 
 #[derive(StructOpt)]
@@ -314,13 +737,62 @@ enum SubCommand {
         image: String,
         /// The width of the output image.
         #[structopt(short, long)]
-        width: u32,
+        width: Option<u32>,
         /// The height of the output image.
         #[structopt(short, long)]
-        height: u32,
-        /// The number of colours in the output image.
+        height: Option<u32>,
+        /// How to fit the source image into the requested grid.
+        /// Options: `exact` (default, stretches to fit), `fit-width`,
+        /// `fit-height`, `fit` (preserves aspect ratio, fits inside
+        /// the box) and `fill` (preserves aspect ratio, covers the
+        /// box, then center-crops).
+        #[structopt(short, long, default_value = "exact")]
+        mode: String,
+        /// The number of colours in the output image. Must be
+        /// between 1 and 8, since each colour gets its own symbol
+        /// on the chart and only 8 distinct symbols exist.
         #[structopt(short, long)]
         colours: u8,
+        /// The algorithm used to build the output palette. Options:
+        /// `median-cut` (default, cheap) or `kmeans` (Lloyd's
+        /// k-means in CIELAB space, more perceptually accurate but
+        /// slower).
+        #[structopt(long, default_value = "median-cut")]
+        algorithm: String,
+        /// Floyd-Steinberg dither the quantized image instead of
+        /// flatly snapping each pixel to its nearest palette
+        /// colour. Trades flat colour fields for a stippled
+        /// gradient, which can make charts with very few colours
+        /// read better from a distance.
+        #[structopt(long)]
+        dither: bool,
+        /// The format(s) the processed chart is saved in, comma-
+        /// separated to export more than one, e.g. `webp,png`.
+        /// Options: `png` (default), `webp` (lossless) or `svg` (a
+        /// vector grid of filled cells, which scales cleanly for
+        /// large-format printing).
+        #[structopt(short, long, default_value = "png")]
+        format: String,
+        /// Print a machine-readable JSON summary (project name,
+        /// every artifact's path and dimensions, and the palette as
+        /// hex codes) instead of the human-readable report.
+        #[structopt(long)]
+        json: bool,
+        /// Advanced: drive image processing with a composable
+        /// pipeline spec instead of the default blur/resize/
+        /// quantize/grid sequence, e.g.
+        /// `--pipeline "blur=3,resize=40x50,quantize=6:dither,grid"`.
+        /// When set, `--width`/`--height`/`--mode`/`--colours`/
+        /// `--dither` are ignored in favour of the stages named in
+        /// the spec.
+        #[structopt(long)]
+        pipeline: Option<String>,
+        /// Number of threads to quantize with. Nearest-colour
+        /// assignment is embarrassingly parallel (each output pixel
+        /// is independent), so this scales close to linearly.
+        /// Defaults to the number of logical cores.
+        #[structopt(long)]
+        threads: Option<usize>,
     },
     /// Remove an existing project.
     Remove {
@@ -337,10 +809,24 @@ enum SubCommand {
         /// original / processed.
         #[structopt(short, long)]
         r#type: Option<String>,
+        /// Render the image directly in the terminal as a grid of
+        /// colored block characters instead of opening a viewer.
+        #[structopt(long)]
+        ascii: bool,
+        /// Number of columns to render the `--ascii` preview at.
+        #[structopt(long, default_value = "80")]
+        columns: u32,
+        /// Print a machine-readable JSON summary (image type, path
+        /// and dimensions) instead of displaying the image.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Regenerate and print a project's written, row-by-row
+    /// instructions from its chart.
+    Instructions {
+        /// Name of the project to generate instructions for.
+        name: String,
     },
-    // Instructions {
-    //     _name: String,
-    // },
 }
 
 #[derive(StructOpt)]
@@ -358,8 +844,72 @@ fn main() {
             image,
             width,
             height,
+            mode,
             colours,
+            algorithm,
+            dither,
+            format,
+            json,
+            pipeline,
+            threads,
         } => {
+            let algorithm = match QuantizeAlgorithm::from_str(&algorithm) {
+                Ok(algorithm) => algorithm,
+                Err(_) => {
+                    eprintln!(
+                        "Quantization algorithm `{}` does not exist. It should be one of `median-cut`, `kmeans`",
+                        algorithm
+                    );
+                    process::exit(1);
+                }
+            };
+            if colours == 0 || colours as usize > SYMBOL_COUNT {
+                eprintln!(
+                    "`--colours` must be between 1 and {} (the chart can only draw {} distinct symbols)",
+                    SYMBOL_COUNT, SYMBOL_COUNT
+                );
+                process::exit(1);
+            }
+            let threads = threads.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+            let resize_mode = match pipeline {
+                Some(_) => None,
+                None => Some(
+                    match ResizeModeArg::from_str(&mode)
+                        .map_err(|_| {
+                            Error::External(format!(
+                                "Resize mode `{}` does not exist. It should be one of `exact`, `fit-width`, `fit-height`, `fit`, `fill`",
+                                mode
+                            ))
+                        })
+                        .and_then(|kind| kind.into_resize_mode(width, height))
+                    {
+                        Err(err) => {
+                            eprintln!("Could not parse resize mode. Error: {}", err);
+                            process::exit(1);
+                        }
+                        Ok(resize_mode) => resize_mode,
+                    },
+                ),
+            };
+            let output_formats: Vec<OutputFormat> = format
+                .split(',')
+                .map(|token| {
+                    OutputFormat::from_str(token.trim()).map_err(|_| {
+                        format!(
+                            "Output format `{}` does not exist. It should be one of `png`, `webp`, `svg`",
+                            token
+                        )
+                    })
+                })
+                .collect::<Result<Vec<OutputFormat>, String>>()
+                .unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                });
             let project = Project::new(&name);
             match project {
                 Err(err) => {
@@ -375,18 +925,95 @@ fn main() {
                         }
                         _ => (),
                     }
-                    match project.transform_image(width, height, colours) {
+                    let transform_result = match (&pipeline, resize_mode) {
+                        (Some(spec), _) => {
+                            project.transform_with_pipeline(spec, &output_formats, threads)
+                        }
+                        (None, Some(resize_mode)) => project.transform_image(
+                            resize_mode,
+                            colours,
+                            algorithm,
+                            &output_formats,
+                            dither,
+                            threads,
+                        ),
+                        (None, None) => unreachable!(
+                            "resize_mode is only None when --pipeline is set"
+                        ),
+                    };
+                    match transform_result {
                         Err(err) => {
                             eprintln!("Could not transform image. Error: {}", err);
                             project.remove_project();
                             process::exit(1);
                         }
-                        _ => (),
+                        Ok(outputs) if json => {
+                            let stages: Vec<String> =
+                                outputs.stages.iter().map(artifact_json).collect();
+                            let extra_formats: Vec<String> =
+                                outputs.extra_formats.iter().map(artifact_json).collect();
+                            let palette: Vec<String> =
+                                outputs.palette.iter().map(hex_colour).map(|hex| format!("\"{}\"", hex)).collect();
+                            println!(
+                                "{{\"name\":\"{}\",\"path\":\"{}\",\"grid_width\":{},\"grid_height\":{},\"resized_down\":{},\"quantized\":{},\"processed\":{},\"legend_image\":{},\"legend_text\":\"{}\",\"stages\":[{}],\"extra_formats\":[{}],\"palette\":[{}]}}",
+                                json_escape(&project.name),
+                                json_escape(project.path.to_str().unwrap_or("")),
+                                project.grid_size.0,
+                                project.grid_size.1,
+                                artifact_json(&outputs.resized_down),
+                                artifact_json(&outputs.quantized),
+                                artifact_json(&outputs.processed),
+                                artifact_json(&outputs.legend_image),
+                                json_escape(outputs.legend_text.to_str().unwrap_or("")),
+                                stages.join(","),
+                                extra_formats.join(","),
+                                palette.join(","),
+                            );
+                        }
+                        Ok(outputs) => {
+                            println!(
+                                "  resized down: {:?} ({}x{})",
+                                outputs.resized_down.path,
+                                outputs.resized_down.width,
+                                outputs.resized_down.height
+                            );
+                            println!(
+                                "  quantized:    {:?} ({}x{})",
+                                outputs.quantized.path, outputs.quantized.width, outputs.quantized.height
+                            );
+                            println!(
+                                "  processed:    {:?} ({}x{})",
+                                outputs.processed.path, outputs.processed.width, outputs.processed.height
+                            );
+                            println!(
+                                "  legend image: {:?} ({}x{})",
+                                outputs.legend_image.path,
+                                outputs.legend_image.width,
+                                outputs.legend_image.height
+                            );
+                            println!("  legend text:  {:?}", outputs.legend_text);
+                            if !outputs.stages.is_empty() {
+                                for stage in &outputs.stages {
+                                    println!(
+                                        "  stage:        {:?} ({}x{})",
+                                        stage.path, stage.width, stage.height
+                                    );
+                                }
+                            }
+                            for extra in &outputs.extra_formats {
+                                println!(
+                                    "  extra format: {:?} ({}x{})",
+                                    extra.path, extra.width, extra.height
+                                );
+                            }
+                        }
+                    }
+                    if !json {
+                        println!(
+                            "Succsessfully created project {}, stored at {:?} ({}x{} stitches)",
+                            project.name, project.path, project.grid_size.0, project.grid_size.1
+                        );
                     }
-                    println!(
-                        "Succsessfully created project {}, stored at {:?}",
-                        project.name, project.path
-                    );
                 }
             }
         }
@@ -403,7 +1030,13 @@ fn main() {
                 }
             }
         }
-        SubCommand::Show { name, r#type } => {
+        SubCommand::Show {
+            name,
+            r#type,
+            ascii,
+            columns,
+            json,
+        } => {
             let type_string = r#type.unwrap_or("processed".to_string());
             let project = Project::load(&name);
             match project {
@@ -412,7 +1045,7 @@ fn main() {
                     process::exit(1);
                 }
                 Ok(project) => match ImageType::from_str(&type_string.as_str()) {
-                    Ok(image_type) => match project.show(image_type) {
+                    Ok(image_type) => match project.show(image_type, &type_string, ascii, columns, json) {
                         Err(err) => {
                             eprintln!("Failed to display image. Error: {}", err);
                             process::exit(1);
@@ -426,5 +1059,33 @@ fn main() {
                 },
             }
         }
+        SubCommand::Instructions { name } => {
+            let project = Project::load(&name);
+            match project {
+                Err(err) => {
+                    eprintln!("Could not load existing project. Error: {}", err);
+                    process::exit(1);
+                }
+                Ok(mut project) => match project.regenerate_instructions() {
+                    Err(err) => {
+                        eprintln!("Could not generate instructions. Error: {}", err);
+                        process::exit(1);
+                    }
+                    Ok(()) => match project.instructions.as_ref() {
+                        None => {
+                            eprintln!("Project `{}` has no palette to generate instructions from.", name);
+                            process::exit(1);
+                        }
+                        Some(instructions) => match fs::read_to_string(&instructions.text_path) {
+                            Ok(contents) => print!("{}", contents),
+                            Err(err) => {
+                                eprintln!("Could not read instructions. Error: {}", err);
+                                process::exit(1);
+                            }
+                        },
+                    },
+                },
+            }
+        }
     }
 }
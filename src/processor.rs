@@ -0,0 +1,281 @@
+use crate::err::Error;
+use crate::utils::{
+    add_symbol_chart, dither_floyd_steinberg, median_cut_palette, snap_to_palette_parallel,
+    Artifact, ResizeMode, SYMBOL_COUNT,
+};
+use image::imageops::{blur, FilterType};
+use image::{DynamicImage, Rgb};
+use std::path::{Path, PathBuf};
+
+/// A single stage in an image-processing pipeline. Each stage knows
+/// how to parse itself out of the `key=value` tokens of a
+/// `--pipeline` string, how to transform an image, and how to name
+/// the subfolder its intermediate output is cached under, so a
+/// `--pipeline` run leaves predictable, per-step files behind
+/// instead of the fixed `resized_down.png`/`resized_up.png`/
+/// `quantized.png` names the old hard-coded sequence used.
+pub trait Processor {
+    /// Parses a `key=value` pipeline token into a processor
+    /// instance, or `None` if `key` isn't this processor's name.
+    fn parse(key: &str, value: &str) -> Option<Self>
+    where
+        Self: Sized;
+    /// Applies this stage to `img`.
+    fn transform(&mut self, img: DynamicImage) -> Result<DynamicImage, Error>;
+    /// The subfolder name this stage's intermediate output is
+    /// stored under, e.g. `blur-3`.
+    fn cache_key(&self) -> String;
+}
+
+/// Gaussian-blurs the image with the given sigma. Token: `blur=3.0`.
+pub struct BlurProcessor {
+    pub sigma: f32,
+}
+
+impl Processor for BlurProcessor {
+    fn parse(key: &str, value: &str) -> Option<Self> {
+        if key != "blur" {
+            return None;
+        }
+        value.parse::<f32>().ok().map(|sigma| BlurProcessor { sigma })
+    }
+
+    fn transform(&mut self, img: DynamicImage) -> Result<DynamicImage, Error> {
+        Ok(DynamicImage::ImageRgba8(blur(&img, self.sigma)))
+    }
+
+    fn cache_key(&self) -> String {
+        format!("blur-{}", self.sigma)
+    }
+}
+
+/// Resizes the image to an exact `WxH` stitch grid. Token:
+/// `resize=40x50`.
+pub struct ResizeProcessor {
+    pub mode: ResizeMode,
+}
+
+impl Processor for ResizeProcessor {
+    fn parse(key: &str, value: &str) -> Option<Self> {
+        if key != "resize" {
+            return None;
+        }
+        let (width, height) = value.split_once('x')?;
+        let width: u32 = width.parse().ok()?;
+        let height: u32 = height.parse().ok()?;
+        Some(ResizeProcessor {
+            mode: ResizeMode::Exact(width, height),
+        })
+    }
+
+    fn transform(&mut self, img: DynamicImage) -> Result<DynamicImage, Error> {
+        let (resized, _, _) = crate::utils::resize_with_mode(&img, self.mode);
+        Ok(resized)
+    }
+
+    fn cache_key(&self) -> String {
+        match self.mode {
+            ResizeMode::Exact(width, height) => format!("resize-{}x{}", width, height),
+            _ => "resize".to_string(),
+        }
+    }
+}
+
+/// Reduces the image to `colours` colours via `median_cut_palette`.
+/// Token: `quantize=6`, or `quantize=6:dither` to Floyd-Steinberg
+/// dither onto the palette instead of flatly snapping each pixel to
+/// its nearest colour. The resulting palette is stashed for the
+/// `grid` stage (or any other downstream consumer) to pick up via
+/// `palette()`.
+pub struct QuantizeProcessor {
+    pub colours: u8,
+    pub dither: bool,
+    palette: Vec<Rgb<u8>>,
+}
+
+impl QuantizeProcessor {
+    /// The palette produced by the most recent `transform` call.
+    pub fn palette(&self) -> Vec<Rgb<u8>> {
+        self.palette.clone()
+    }
+}
+
+impl Processor for QuantizeProcessor {
+    fn parse(key: &str, value: &str) -> Option<Self> {
+        if key != "quantize" {
+            return None;
+        }
+        let (colours, dither) = match value.split_once(':') {
+            Some((colours, "dither")) => (colours, true),
+            Some(_) => return None,
+            None => (value, false),
+        };
+        colours
+            .parse::<u8>()
+            .ok()
+            .filter(|&colours| colours > 0 && (colours as usize) <= SYMBOL_COUNT)
+            .map(|colours| QuantizeProcessor {
+                colours,
+                dither,
+                palette: Vec::new(),
+            })
+    }
+
+    fn transform(&mut self, img: DynamicImage) -> Result<DynamicImage, Error> {
+        let mut buffer = img.to_rgb8();
+        let palette = median_cut_palette(
+            &buffer.pixels().copied().collect::<Vec<_>>(),
+            self.colours as usize,
+        );
+        if self.dither {
+            dither_floyd_steinberg(&mut buffer, &palette);
+        } else {
+            snap_to_palette_parallel(&mut buffer, &palette);
+        }
+        self.palette = palette;
+        Ok(DynamicImage::ImageRgb8(buffer))
+    }
+
+    fn cache_key(&self) -> String {
+        if self.dither {
+            format!("quantize-{}-dither", self.colours)
+        } else {
+            format!("quantize-{}", self.colours)
+        }
+    }
+}
+
+/// Pixels-per-stitch used when `GridProcessor` upscales a
+/// (typically tiny, one-pixel-per-stitch) quantized image before
+/// drawing the grid and symbols over it.
+const CELL_PIXELS: u32 = 20;
+
+/// Stamps a symbol chart over the image, upscaling it first so each
+/// stitch gets a legible cell. Token: `grid`.
+pub struct GridProcessor {
+    pub grid_width: u32,
+    pub grid_height: u32,
+    pub palette: Vec<Rgb<u8>>,
+}
+
+impl Processor for GridProcessor {
+    fn parse(key: &str, _value: &str) -> Option<Self> {
+        if key != "grid" {
+            return None;
+        }
+        // `grid` needs the stitch grid and palette from earlier
+        // stages, so it's always constructed directly by the
+        // pipeline runner rather than through `parse`.
+        None
+    }
+
+    fn transform(&mut self, img: DynamicImage) -> Result<DynamicImage, Error> {
+        let target_width = (self.grid_width * CELL_PIXELS).max(1);
+        let target_height = (self.grid_height * CELL_PIXELS).max(1);
+        let mut upscaled = img.resize_exact(target_width, target_height, FilterType::Nearest);
+        add_symbol_chart(&mut upscaled, self.grid_width, self.grid_height, &self.palette);
+        Ok(upscaled)
+    }
+
+    fn cache_key(&self) -> String {
+        "grid".to_string()
+    }
+}
+
+/// One stage of a parsed `--pipeline` string.
+pub enum Stage {
+    Blur(BlurProcessor),
+    Resize(ResizeProcessor),
+    Quantize(QuantizeProcessor),
+    Grid,
+}
+
+/// Parses a `--pipeline` string such as `"blur=3,resize=40x50,
+/// quantize=6,grid"` into an ordered list of stages. Stages may be
+/// reordered, omitted, or repeated.
+pub fn parse_pipeline(spec: &str) -> Result<Vec<Stage>, Error> {
+    spec.split(',')
+        .map(|token| {
+            let token = token.trim();
+            let (key, value) = token.split_once('=').unwrap_or((token, ""));
+            match key {
+                "blur" => BlurProcessor::parse(key, value).map(Stage::Blur),
+                "resize" => ResizeProcessor::parse(key, value).map(Stage::Resize),
+                "quantize" => QuantizeProcessor::parse(key, value).map(Stage::Quantize),
+                "grid" => Some(Stage::Grid),
+                _ => None,
+            }
+            .ok_or_else(|| Error::External(format!("invalid pipeline stage `{}`", token)))
+        })
+        .collect()
+}
+
+/// Runs a parsed pipeline over `image`, saving each stage's
+/// intermediate output under `cache_dir/<index>-<cache_key>/output.png`
+/// so a run leaves predictable, per-step subfolders instead of a handful of
+/// scattered, fixed filenames. Returns the final image, the quantized
+/// chart as it stood right before any `grid` stage stamped a symbol
+/// over it (for sampling real stitch colours — see `quantized_chart`
+/// below), the stitch grid (columns, rows) set by the last `resize`
+/// stage, the palette produced by the last `quantize` stage, and the
+/// list of intermediate artifacts.
+pub fn run_pipeline(
+    stages: &mut [Stage],
+    mut image: DynamicImage,
+    cache_dir: &Path,
+) -> Result<(DynamicImage, DynamicImage, (u32, u32), Vec<Rgb<u8>>, Vec<Artifact>), Error> {
+    std::fs::create_dir_all(cache_dir).map_err(|e| Error::External(e.to_string()))?;
+    let mut grid_size = (image.width(), image.height());
+    let mut palette: Vec<Rgb<u8>> = Vec::new();
+    let mut artifacts = Vec::with_capacity(stages.len());
+    // The image as it stood right before the most recent `grid`
+    // stage stamped a symbol over every cell center; sampling stitch
+    // colours (stitch counts, written instructions) from this instead
+    // of the final image avoids sampling the symbol's ink colour.
+    // Stays equal to the final image if no `grid` stage ever runs.
+    let mut quantized_chart = image.clone();
+
+    for (index, stage) in stages.iter_mut().enumerate() {
+        let is_grid = matches!(stage, Stage::Grid);
+        image = match stage {
+            Stage::Blur(processor) => processor.transform(image)?,
+            Stage::Resize(processor) => {
+                let resized = processor.transform(image)?;
+                grid_size = (resized.width(), resized.height());
+                resized
+            }
+            Stage::Quantize(processor) => {
+                let quantized = processor.transform(image)?;
+                palette = processor.palette();
+                quantized
+            }
+            Stage::Grid => {
+                let mut grid_processor = GridProcessor {
+                    grid_width: grid_size.0,
+                    grid_height: grid_size.1,
+                    palette: palette.clone(),
+                };
+                grid_processor.transform(image)?
+            }
+        };
+        if !is_grid {
+            quantized_chart = image.clone();
+        }
+        let cache_key = match stage {
+            Stage::Blur(processor) => processor.cache_key(),
+            Stage::Resize(processor) => processor.cache_key(),
+            Stage::Quantize(processor) => processor.cache_key(),
+            Stage::Grid => "grid".to_string(),
+        };
+        let mut path: PathBuf = cache_dir.to_path_buf();
+        path.push(format!("{}-{}", index, cache_key));
+        std::fs::create_dir_all(&path).map_err(|e| Error::External(e.to_string()))?;
+        path.push("output.png");
+        image
+            .save(&path)
+            .map_err(|e| Error::External(e.to_string()))?;
+        artifacts.push(Artifact::new(path, image.width(), image.height()));
+    }
+
+    Ok((image, quantized_chart, grid_size, palette, artifacts))
+}
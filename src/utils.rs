@@ -1,17 +1,116 @@
+extern crate exif;
 extern crate image;
 extern crate imageproc;
-extern crate palette_extract;
 extern crate plotters;
+extern crate rand;
+#[cfg(feature = "raw")]
+extern crate rawloader;
+extern crate rayon;
 
 use crate::err::Error;
+use image::imageops::FilterType;
+use image::io::Reader as ImageReader;
 use image::DynamicImage;
+use image::GenericImageView;
 use image::ImageFormat;
-use image::{Pixel, Rgb};
-use imageproc::drawing::draw_line_segment_mut;
-use palette_extract::Color;
+use image::{Pixel, Rgb, RgbImage};
+use imageproc::drawing::{
+    draw_filled_circle_mut, draw_filled_rect_mut, draw_hollow_circle_mut, draw_line_segment_mut,
+    draw_polygon_mut,
+};
+use imageproc::point::Point;
+use imageproc::rect::Rect;
 use plotters::prelude::*;
+use rand::Rng;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use strum_macros::EnumString;
+
+/// Determines how the source image is fitted into the requested
+/// stitch grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Resize to exactly `(width, height)`, distorting the aspect
+    /// ratio if the source doesn't match.
+    Exact(u32, u32),
+    /// Resize to the given width, deriving the height from the
+    /// source aspect ratio.
+    FitWidth(u32),
+    /// Resize to the given height, deriving the width from the
+    /// source aspect ratio.
+    FitHeight(u32),
+    /// Resize to the largest size that fits inside `(width, height)`
+    /// while preserving the aspect ratio.
+    Fit(u32, u32),
+    /// Resize to the smallest size that covers `(width, height)`
+    /// while preserving the aspect ratio, then center-crop to it.
+    Fill(u32, u32),
+}
+
+impl ResizeMode {
+    /// Computes the actual stitch grid dimensions (columns, rows)
+    /// that this mode will produce for a source image of the given
+    /// size, before any downsampling happens.
+    pub fn target_dimensions(&self, source_width: u32, source_height: u32) -> (u32, u32) {
+        let ratio = source_width as f64 / source_height as f64;
+        match *self {
+            ResizeMode::Exact(width, height) => (width, height),
+            ResizeMode::FitWidth(width) => (width, (width as f64 / ratio).round().max(1.0) as u32),
+            ResizeMode::FitHeight(height) => {
+                ((height as f64 * ratio).round().max(1.0) as u32, height)
+            }
+            ResizeMode::Fit(width, height) => {
+                if (width as f64 / height as f64) > ratio {
+                    (
+                        (height as f64 * ratio).round().max(1.0) as u32,
+                        height,
+                    )
+                } else {
+                    (
+                        width,
+                        (width as f64 / ratio).round().max(1.0) as u32,
+                    )
+                }
+            }
+            ResizeMode::Fill(width, height) => (width, height),
+        }
+    }
+}
+
+/// Resizes `image` according to `mode`, returning the resized image
+/// along with the actual stitch grid (columns, rows) it was fitted
+/// to. For `Fill`, the image is resized to cover the requested box
+/// and then center-cropped down to it.
+pub fn resize_with_mode(image: &DynamicImage, mode: ResizeMode) -> (DynamicImage, u32, u32) {
+    let (source_width, source_height) = image.dimensions();
+    let (grid_width, grid_height) = mode.target_dimensions(source_width, source_height);
+    match mode {
+        ResizeMode::Fill(width, height) => {
+            let ratio = source_width as f64 / source_height as f64;
+            let (cover_width, cover_height) = if (width as f64 / height as f64) > ratio {
+                (width, (width as f64 / ratio).round().max(1.0) as u32)
+            } else {
+                ((height as f64 * ratio).round().max(1.0) as u32, height)
+            };
+            let resized = image.resize_exact(cover_width, cover_height, FilterType::Nearest);
+            let x = (cover_width.saturating_sub(width)) / 2;
+            let y = (cover_height.saturating_sub(height)) / 2;
+            (resized.crop_imm(x, y, width, height), width, height)
+        }
+        _ => (
+            image.resize_exact(grid_width, grid_height, FilterType::Nearest),
+            grid_width,
+            grid_height,
+        ),
+    }
+}
 
 /// Function to draw a grid over the pixels of an image.
 /// The grid size is determined by the width and height inputs,
@@ -81,22 +180,355 @@ pub fn plot_image_with_axes(
     Ok(())
 }
 
-/// Convert a `Color` instance from `palette_extract` crate into
-/// an `Rgb` instance from the `image` crate.
-pub fn colour2rgb(colour: Color) -> Rgb<u8> {
-    Rgb::from([colour.r, colour.g, colour.b])
+/// A colour in the CIELAB colour space (D65 white point, 2-degree
+/// observer), used so that colour distances match human perception
+/// rather than raw sRGB channel differences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Lab {
+    l: f32,
+    a: f32,
+    b: f32,
+}
+
+const REF_WHITE_X: f32 = 0.95047;
+const REF_WHITE_Y: f32 = 1.0;
+const REF_WHITE_Z: f32 = 1.08883;
+
+fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let v = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round().clamp(0.0, 255.0) as u8
 }
 
+fn lab_forward(t: f32) -> f32 {
+    const DELTA: f32 = 6.0 / 29.0;
+    if t > DELTA.powi(3) {
+        t.cbrt()
+    } else {
+        t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+    }
+}
+
+fn lab_inverse(t: f32) -> f32 {
+    const DELTA: f32 = 6.0 / 29.0;
+    if t > DELTA {
+        t.powi(3)
+    } else {
+        3.0 * DELTA * DELTA * (t - 4.0 / 29.0)
+    }
+}
+
+/// Converts an sRGB colour into CIELAB, via linear RGB and CIE XYZ
+/// (D65 illuminant).
+fn rgb_to_lab(colour: &Rgb<u8>) -> Lab {
+    let channels = colour.channels();
+    let r = srgb_to_linear(channels[0]);
+    let g = srgb_to_linear(channels[1]);
+    let b = srgb_to_linear(channels[2]);
+    let x = r * 0.4124564 + g * 0.3575761 + b * 0.1804375;
+    let y = r * 0.2126729 + g * 0.7151522 + b * 0.0721750;
+    let z = r * 0.0193339 + g * 0.1191920 + b * 0.9503041;
+    let fx = lab_forward(x / REF_WHITE_X);
+    let fy = lab_forward(y / REF_WHITE_Y);
+    let fz = lab_forward(z / REF_WHITE_Z);
+    Lab {
+        l: 116.0 * fy - 16.0,
+        a: 500.0 * (fx - fy),
+        b: 200.0 * (fy - fz),
+    }
+}
+
+/// Converts a CIELAB colour back into sRGB, clamping to the valid
+/// `[0, 255]` range on the final channel write.
+fn lab_to_rgb(lab: &Lab) -> Rgb<u8> {
+    let fy = (lab.l + 16.0) / 116.0;
+    let fx = fy + lab.a / 500.0;
+    let fz = fy - lab.b / 200.0;
+    let x = REF_WHITE_X * lab_inverse(fx);
+    let y = REF_WHITE_Y * lab_inverse(fy);
+    let z = REF_WHITE_Z * lab_inverse(fz);
+    let r = x * 3.2404542 + y * -1.5371385 + z * -0.4985314;
+    let g = x * -0.9692660 + y * 1.8760108 + z * 0.0415560;
+    let b = x * 0.0556434 + y * -0.2040259 + z * 1.0572252;
+    Rgb::from([linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b)])
+}
+
+fn lab_distance_sq(l1: &Lab, l2: &Lab) -> f32 {
+    (l2.l - l1.l).powf(2.0) + (l2.a - l1.a).powf(2.0) + (l2.b - l1.b).powf(2.0)
+}
+
+/// Perceptual distance (CIE76 ΔE, i.e. Euclidean distance in CIELAB)
+/// between two sRGB colours.
 pub fn colour_distance(c1: &Rgb<u8>, c2: &Rgb<u8>) -> f32 {
-    let ch1 = c1.channels();
-    let ch2 = c2.channels();
-    let r1 = ch1[0] as f32;
-    let r2 = ch2[0] as f32;
-    let g1 = ch1[1] as f32;
-    let g2 = ch2[1] as f32;
-    let b1 = ch1[2] as f32;
-    let b2 = ch2[2] as f32;
-    f32::sqrt((r2 - r1).powf(2.0) + (g2 - g1).powf(2.0) + (b2 - b1).powf(2.0))
+    lab_distance_sq(&rgb_to_lab(c1), &rgb_to_lab(c2)).sqrt()
+}
+
+/// The quantization algorithm used to build the output palette.
+/// Selected on the command line with `--algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+pub enum QuantizeAlgorithm {
+    /// Recursively split the box of pixels with the widest channel
+    /// spread at its median (see `median_cut_palette`). Cheap, and
+    /// the default.
+    #[strum(serialize = "median-cut")]
+    MedianCut,
+    /// Lloyd's k-means in CIELAB space (see `kmeans_palette`).
+    /// Perceptually more accurate, at the cost of several passes
+    /// over the pixel data.
+    #[strum(serialize = "kmeans")]
+    KMeans,
+}
+
+impl QuantizeAlgorithm {
+    /// Builds a palette of `k` colours out of `pixels` with this
+    /// algorithm.
+    pub fn build_palette(self, pixels: &[Rgb<u8>], k: usize) -> Vec<Rgb<u8>> {
+        match self {
+            QuantizeAlgorithm::MedianCut => median_cut_palette(pixels, k),
+            QuantizeAlgorithm::KMeans => kmeans_palette(pixels, k),
+        }
+    }
+}
+
+/// Builds a palette of `k` colours out of `pixels` by running Lloyd's
+/// k-means in CIELAB space, seeded with k-means++. Each unique colour
+/// is weighted by how many times it appears, so frequent colours pull
+/// centroids towards them. Stops once the largest centroid shift drops
+/// below ~0.5 ΔE, or after 30 iterations, whichever comes first. Any
+/// cluster that ends up empty is re-seeded to the pixel farthest from
+/// its centroid.
+pub fn kmeans_palette(pixels: &[Rgb<u8>], k: usize) -> Vec<Rgb<u8>> {
+    let mut counts: HashMap<Rgb<u8>, u32> = HashMap::new();
+    for pixel in pixels {
+        *counts.entry(*pixel).or_insert(0) += 1;
+    }
+    let colours: Vec<Rgb<u8>> = counts.keys().copied().collect();
+    let weights: Vec<u32> = colours.iter().map(|c| counts[c]).collect();
+    let labs: Vec<Lab> = colours.iter().map(rgb_to_lab).collect();
+    let k = k.min(labs.len()).max(1);
+
+    let mut rng = rand::thread_rng();
+    let mut centroids: Vec<Lab> = Vec::with_capacity(k);
+    centroids.push(labs[rng.gen_range(0..labs.len())]);
+    while centroids.len() < k {
+        let distances: Vec<f32> = labs
+            .iter()
+            .map(|l| {
+                centroids
+                    .iter()
+                    .map(|c| lab_distance_sq(l, c))
+                    .fold(f32::MAX, f32::min)
+            })
+            .collect();
+        let total: f32 = distances.iter().sum();
+        if total <= 0.0 {
+            centroids.push(labs[rng.gen_range(0..labs.len())]);
+            continue;
+        }
+        let mut threshold = rng.gen_range(0.0..total);
+        let mut chosen = labs.len() - 1;
+        for (i, distance) in distances.iter().enumerate() {
+            if threshold < *distance {
+                chosen = i;
+                break;
+            }
+            threshold -= distance;
+        }
+        centroids.push(labs[chosen]);
+    }
+
+    let mut assignments = vec![0usize; labs.len()];
+    for iteration in 0..30 {
+        for (i, l) in labs.iter().enumerate() {
+            let (closest, _) = centroids
+                .iter()
+                .enumerate()
+                .map(|(j, c)| (j, lab_distance_sq(l, c)))
+                .fold((0, f32::MAX), |best, cur| if cur.1 < best.1 { cur } else { best });
+            assignments[i] = closest;
+        }
+
+        let mut sums = vec![(0f64, 0f64, 0f64, 0f64); k];
+        for (i, l) in labs.iter().enumerate() {
+            let cluster = assignments[i];
+            let weight = weights[i] as f64;
+            sums[cluster].0 += l.l as f64 * weight;
+            sums[cluster].1 += l.a as f64 * weight;
+            sums[cluster].2 += l.b as f64 * weight;
+            sums[cluster].3 += weight;
+        }
+
+        let mut max_shift: f32 = 0.0;
+        for (cluster, sum) in sums.iter().enumerate() {
+            if sum.3 == 0.0 {
+                let (farthest, _) = labs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| (i, lab_distance_sq(l, &centroids[cluster])))
+                    .fold((0, -1.0f32), |best, cur| if cur.1 > best.1 { cur } else { best });
+                centroids[cluster] = labs[farthest];
+                continue;
+            }
+            let new_centroid = Lab {
+                l: (sum.0 / sum.3) as f32,
+                a: (sum.1 / sum.3) as f32,
+                b: (sum.2 / sum.3) as f32,
+            };
+            max_shift = max_shift.max(lab_distance_sq(&centroids[cluster], &new_centroid).sqrt());
+            centroids[cluster] = new_centroid;
+        }
+
+        if max_shift < 0.5 || iteration == 29 {
+            break;
+        }
+    }
+
+    centroids.iter().map(lab_to_rgb).collect()
+}
+
+/// A box of pixels in an octree-free median-cut quantizer: the
+/// slice of `pixels` it owns, over which the caller picks the
+/// widest channel and splits at the median.
+struct ColourBox {
+    pixels: Vec<Rgb<u8>>,
+}
+
+impl ColourBox {
+    /// The channel (0 = R, 1 = G, 2 = B) with the largest
+    /// `max - min` spread, and that spread.
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let (min, max) = self
+                    .pixels
+                    .iter()
+                    .map(|p| p.channels()[channel])
+                    .fold((u8::MAX, u8::MIN), |(min, max), v| (min.min(v), max.max(v)));
+                (channel, max - min)
+            })
+            .max_by_key(|&(_, spread)| spread)
+            .unwrap()
+    }
+
+    /// The average colour of every pixel in this box.
+    fn average(&self) -> Rgb<u8> {
+        let (r, g, b) = self.pixels.iter().fold((0u64, 0u64, 0u64), |acc, p| {
+            let c = p.channels();
+            (acc.0 + c[0] as u64, acc.1 + c[1] as u64, acc.2 + c[2] as u64)
+        });
+        let n = self.pixels.len() as u64;
+        Rgb::from([(r / n) as u8, (g / n) as u8, (b / n) as u8])
+    }
+}
+
+/// Builds a palette of (up to) `k` colours out of `pixels` via
+/// median-cut: starting from a single box holding every pixel,
+/// repeatedly picks the box whose R, G or B channel has the widest
+/// `max - min` spread, sorts that box's pixels on that channel, and
+/// splits it in two at the median. Stops once there are `k` boxes
+/// (or no box has more than one distinct colour left to split), then
+/// averages each box into a palette entry.
+pub fn median_cut_palette(pixels: &[Rgb<u8>], k: usize) -> Vec<Rgb<u8>> {
+    if pixels.is_empty() || k == 0 {
+        return Vec::new();
+    }
+    let mut boxes = vec![ColourBox {
+        pixels: pixels.to_vec(),
+    }];
+
+    while boxes.len() < k {
+        let splittable = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.pixels.len() > 1 && b.widest_channel().1 > 0)
+            .max_by_key(|(_, b)| b.widest_channel().1);
+        let index = match splittable {
+            Some((index, _)) => index,
+            None => break,
+        };
+        let mut target = boxes.swap_remove(index);
+        let (channel, _) = target.widest_channel();
+        target
+            .pixels
+            .sort_unstable_by_key(|p| p.channels()[channel]);
+        let mid = target.pixels.len() / 2;
+        let lower = ColourBox {
+            pixels: target.pixels[..mid].to_vec(),
+        };
+        let upper = ColourBox {
+            pixels: target.pixels[mid..].to_vec(),
+        };
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(ColourBox::average).collect()
+}
+
+/// Dithers `image` onto `palette` with Floyd-Steinberg error
+/// diffusion, scanning top-to-bottom, left-to-right. Each pixel is
+/// snapped to its nearest palette colour (by perceptual distance);
+/// the per-channel quantization error `(old - new)` is then spread
+/// to neighbours not yet visited — right (7/16), bottom-left
+/// (3/16), bottom (5/16) and bottom-right (1/16) — skipping
+/// neighbours that fall outside the image. The running error is
+/// accumulated in an `f32` buffer and only clamped to `[0, 255]`
+/// when a pixel is finally written, so error doesn't compound
+/// rounding mistakes as it propagates.
+pub fn dither_floyd_steinberg(image: &mut RgbImage, palette: &[Rgb<u8>]) {
+    let (width, height) = image.dimensions();
+    let mut working: Vec<[f32; 3]> = image
+        .pixels()
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+        .collect();
+    let index = |x: u32, y: u32| (y * width + x) as usize;
+
+    for y in 0..height {
+        for x in 0..width {
+            let old = working[index(x, y)];
+            let old_pixel = Rgb::from([
+                old[0].clamp(0.0, 255.0) as u8,
+                old[1].clamp(0.0, 255.0) as u8,
+                old[2].clamp(0.0, 255.0) as u8,
+            ]);
+            let distances: Vec<f32> = palette.iter().map(|c| colour_distance(c, &old_pixel)).collect();
+            let new_pixel = palette[min_index(&distances)];
+            image.put_pixel(x, y, new_pixel);
+
+            let error = [
+                old[0] - new_pixel[0] as f32,
+                old[1] - new_pixel[1] as f32,
+                old[2] - new_pixel[2] as f32,
+            ];
+            let mut spread = |dx: i64, dy: i64, weight: f32| {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                    return;
+                }
+                let neighbour = &mut working[index(nx as u32, ny as u32)];
+                neighbour[0] += error[0] * weight;
+                neighbour[1] += error[1] * weight;
+                neighbour[2] += error[2] * weight;
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
 }
 
 pub fn min_index(array: &[f32]) -> usize {
@@ -109,15 +541,778 @@ pub fn min_index(array: &[f32]) -> usize {
     i
 }
 
-/// Sets a mutable reference of a pixel in an image to its
-/// closest colour in a given palette reference, which is a
-/// vector of candidate colours.
-pub fn set_closest_colour(pixel: (u32, u32, &mut Rgb<u8>), palette: &[Rgb<u8>]) {
-    let distances: Vec<f32> = palette
+/// Snaps every pixel of `image` to its closest colour in `palette`.
+/// Since quantization input typically has very few distinct colours
+/// (after the blur + downsample + upsample passes), this first
+/// resolves each *unique* colour to its palette match once, in
+/// parallel, and then rewrites the pixel buffer through that cache,
+/// also in parallel — far cheaper than running the full
+/// `pixels x palette` nearest-colour search serially.
+pub fn snap_to_palette_parallel(image: &mut RgbImage, palette: &[Rgb<u8>]) {
+    let unique_colours: Vec<Rgb<u8>> = image.pixels().copied().collect::<HashSet<_>>().into_iter().collect();
+    let cache: HashMap<Rgb<u8>, Rgb<u8>> = unique_colours
+        .par_iter()
+        .map(|colour| {
+            let distances: Vec<f32> = palette.iter().map(|c| colour_distance(c, colour)).collect();
+            (*colour, palette[min_index(&distances)])
+        })
+        .collect();
+
+    image.enumerate_pixels_mut().par_bridge().for_each(|(_, _, pixel)| {
+        if let Some(mapped) = cache.get(pixel) {
+            *pixel = *mapped;
+        }
+    });
+}
+
+/// Returns the index in `palette` of the colour closest to
+/// `colour`.
+fn closest_palette_index(colour: &Rgb<u8>, palette: &[Rgb<u8>]) -> usize {
+    let distances: Vec<f32> = palette.iter().map(|c| colour_distance(c, colour)).collect();
+    min_index(&distances[..])
+}
+
+/// Picks a black or white ink colour, whichever contrasts more
+/// against `background`, so symbols stay legible on both light and
+/// dark swatches.
+fn contrasting_ink(background: &Rgb<u8>) -> image::Rgba<u8> {
+    let channels = background.channels();
+    let luminance =
+        0.299 * channels[0] as f32 + 0.587 * channels[1] as f32 + 0.114 * channels[2] as f32;
+    if luminance > 140.0 {
+        image::Rgba([0u8, 0u8, 0u8, 255u8])
+    } else {
+        image::Rgba([255u8, 255u8, 255u8, 255u8])
+    }
+}
+
+/// Number of visually-distinct shapes `draw_symbol_mut` can draw.
+/// Palettes bigger than this would have to reuse a shape for two
+/// colours, defeating the point of symbol charts (telling stitches
+/// apart without colour), so callers building a palette must cap
+/// `colours` at this value.
+pub const SYMBOL_COUNT: usize = 8;
+
+/// Draws the `index`-th symbol from a fixed, visually-distinct
+/// rotation of shapes (circle, square, triangle, diamond, cross,
+/// ring, ...), centered at `(cx, cy)` and sized to fit within
+/// `radius` pixels. Shapes (rather than glyphs) are used so the
+/// chart reads correctly when printed in black-and-white, without
+/// depending on a bundled font. `index` is expected to be less than
+/// `SYMBOL_COUNT`; callers are responsible for keeping palettes
+/// within that bound so two colours never share a shape.
+fn draw_symbol_mut(image: &mut RgbImage, index: usize, cx: i32, cy: i32, radius: i32, ink: image::Rgba<u8>) {
+    let ink_rgb = Rgb([ink.0[0], ink.0[1], ink.0[2]]);
+    match index % SYMBOL_COUNT {
+        0 => draw_filled_circle_mut(image, (cx, cy), radius, ink_rgb),
+        1 => draw_filled_rect_mut(
+            image,
+            Rect::at(cx - radius, cy - radius).of_size((radius as u32 * 2).max(1), (radius as u32 * 2).max(1)),
+            ink_rgb,
+        ),
+        2 => draw_polygon_mut(
+            image,
+            &[
+                Point::new(cx, cy - radius),
+                Point::new(cx - radius, cy + radius),
+                Point::new(cx + radius, cy + radius),
+            ],
+            ink_rgb,
+        ),
+        3 => draw_polygon_mut(
+            image,
+            &[
+                Point::new(cx, cy - radius),
+                Point::new(cx + radius, cy),
+                Point::new(cx, cy + radius),
+                Point::new(cx - radius, cy),
+            ],
+            ink_rgb,
+        ),
+        4 => {
+            draw_line_segment_mut(
+                image,
+                ((cx - radius) as f32, (cy - radius) as f32),
+                ((cx + radius) as f32, (cy + radius) as f32),
+                ink,
+            );
+            draw_line_segment_mut(
+                image,
+                ((cx - radius) as f32, (cy + radius) as f32),
+                ((cx + radius) as f32, (cy - radius) as f32),
+                ink,
+            );
+        }
+        5 => draw_hollow_circle_mut(image, (cx, cy), radius, ink_rgb),
+        6 => {
+            draw_line_segment_mut(
+                image,
+                ((cx - radius) as f32, cy as f32),
+                ((cx + radius) as f32, cy as f32),
+                ink,
+            );
+            draw_line_segment_mut(
+                image,
+                (cx as f32, (cy - radius) as f32),
+                (cx as f32, (cy + radius) as f32),
+                ink,
+            );
+        }
+        _ => draw_polygon_mut(
+            image,
+            &[
+                Point::new(cx, cy - radius),
+                Point::new(cx + radius, cy - radius / 2),
+                Point::new(cx + radius, cy + radius / 2),
+                Point::new(cx, cy + radius),
+                Point::new(cx - radius, cy + radius / 2),
+                Point::new(cx - radius, cy - radius / 2),
+            ],
+            ink_rgb,
+        ),
+    }
+}
+
+/// Extends `add_grid_to_image`: draws the stitch grid, then, for
+/// every cell, samples its colour (from the cell's center pixel),
+/// finds its index in `palette`, and stamps the matching symbol in
+/// the middle of the cell. This makes the chart followable without
+/// colour (e.g. on a black-and-white print-out).
+pub fn add_symbol_chart(
+    image: &mut DynamicImage,
+    grid_width: u32,
+    grid_height: u32,
+    palette: &[Rgb<u8>],
+) {
+    add_grid_to_image(image, grid_width, grid_height);
+    let width = image.width();
+    let height = image.height();
+    let cell_width = width as f32 / grid_width as f32;
+    let cell_height = height as f32 / grid_height as f32;
+    let radius = (cell_width.min(cell_height) / 3.0).max(1.0) as i32;
+    let mut buffer = image.to_rgb8();
+    for row in 0..grid_height {
+        for col in 0..grid_width {
+            let cx = (col as f32 * cell_width + cell_width / 2.0) as i32;
+            let cy = (row as f32 * cell_height + cell_height / 2.0) as i32;
+            let background = *buffer.get_pixel(cx.max(0) as u32, cy.max(0) as u32);
+            let index = closest_palette_index(&background, palette);
+            let ink = contrasting_ink(&background);
+            draw_symbol_mut(&mut buffer, index, cx, cy, radius, ink);
+        }
+    }
+    *image = DynamicImage::ImageRgb8(buffer);
+}
+
+/// One row of the colour legend: a palette colour, the symbol
+/// standing in for it on the chart, and how many stitches use it.
+pub struct LegendEntry {
+    pub colour: Rgb<u8>,
+    pub symbol_index: usize,
+    pub stitch_count: u32,
+}
+
+/// Counts, for every colour in `palette`, how many grid cells of the
+/// (already quantized) `image` are closest to it.
+pub fn count_stitches(image: &DynamicImage, grid_width: u32, grid_height: u32, palette: &[Rgb<u8>]) -> Vec<u32> {
+    let width = image.width();
+    let height = image.height();
+    let cell_width = width as f32 / grid_width as f32;
+    let cell_height = height as f32 / grid_height as f32;
+    let buffer = image.to_rgb8();
+    let mut counts = vec![0u32; palette.len()];
+    if palette.is_empty() {
+        return counts;
+    }
+    for row in 0..grid_height {
+        for col in 0..grid_width {
+            let cx = (col as f32 * cell_width + cell_width / 2.0).min(width as f32 - 1.0) as u32;
+            let cy = (row as f32 * cell_height + cell_height / 2.0).min(height as f32 - 1.0) as u32;
+            let colour = *buffer.get_pixel(cx, cy);
+            counts[closest_palette_index(&colour, palette)] += 1;
+        }
+    }
+    counts
+}
+
+/// Renders a legend image: one swatch row per palette colour, filled
+/// with the colour and stamped with its symbol.
+pub fn render_legend_image(entries: &[LegendEntry]) -> DynamicImage {
+    const ROW_HEIGHT: u32 = 40;
+    const SWATCH_WIDTH: u32 = 60;
+    let mut buffer = RgbImage::from_pixel(
+        SWATCH_WIDTH,
+        ROW_HEIGHT * entries.len().max(1) as u32,
+        Rgb([255u8, 255u8, 255u8]),
+    );
+    for (i, entry) in entries.iter().enumerate() {
+        let y0 = i as i32 * ROW_HEIGHT as i32;
+        draw_filled_rect_mut(
+            &mut buffer,
+            Rect::at(0, y0).of_size(SWATCH_WIDTH, ROW_HEIGHT),
+            entry.colour,
+        );
+        let ink = contrasting_ink(&entry.colour);
+        draw_symbol_mut(
+            &mut buffer,
+            entry.symbol_index,
+            SWATCH_WIDTH as i32 / 2,
+            y0 + ROW_HEIGHT as i32 / 2,
+            (ROW_HEIGHT as i32 / 3).max(1),
+            ink,
+        );
+    }
+    DynamicImage::ImageRgb8(buffer)
+}
+
+/// Writes a human-readable legend alongside the legend image,
+/// listing each colour's symbol, hex value, and stitch count, plus
+/// the chart totals.
+pub fn write_legend_text(
+    path: &Path,
+    entries: &[LegendEntry],
+    grid_width: u32,
+    grid_height: u32,
+) -> Result<(), Error> {
+    let mut file = File::create(path).map_err(|e| Error::External(e.to_string()))?;
+    let total_stitches: u32 = entries.iter().map(|e| e.stitch_count).sum();
+    writeln!(file, "Intarsia legend").map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file, "Grid: {} columns x {} rows", grid_width, grid_height)
+        .map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file, "Total stitches: {}", total_stitches).map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file).map_err(|e| Error::External(e.to_string()))?;
+    for (i, entry) in entries.iter().enumerate() {
+        let channels = entry.colour.channels();
+        writeln!(
+            file,
+            "symbol #{} | #{:02x}{:02x}{:02x} | rgb({}, {}, {}) | {} stitches",
+            i, channels[0], channels[1], channels[2], channels[0], channels[1], channels[2], entry.stitch_count
+        )
+        .map_err(|e| Error::External(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Samples the (already quantized) `image`'s grid cells and returns,
+/// row by row, the index into `palette` each cell is closest to. This
+/// is the same cell-center sampling `count_stitches`/`add_symbol_chart`
+/// use, but keeps the per-cell indices instead of collapsing them into
+/// totals, so callers can walk the chart stitch by stitch.
+pub fn chart_cell_indices(
+    image: &DynamicImage,
+    grid_width: u32,
+    grid_height: u32,
+    palette: &[Rgb<u8>],
+) -> Vec<Vec<usize>> {
+    let width = image.width();
+    let height = image.height();
+    let cell_width = width as f32 / grid_width as f32;
+    let cell_height = height as f32 / grid_height as f32;
+    let buffer = image.to_rgb8();
+    (0..grid_height)
+        .map(|row| {
+            (0..grid_width)
+                .map(|col| {
+                    let cx = (col as f32 * cell_width + cell_width / 2.0).min(width as f32 - 1.0) as u32;
+                    let cy = (row as f32 * cell_height + cell_height / 2.0).min(height as f32 - 1.0) as u32;
+                    let colour = *buffer.get_pixel(cx, cy);
+                    closest_palette_index(&colour, palette)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// One run of consecutive same-colour stitches within a written row.
+pub struct StitchRun {
+    pub symbol_index: usize,
+    pub count: u32,
+}
+
+/// One row of written instructions: its (1-based, top-to-bottom) row
+/// number, the direction it's worked in, and its run-length-encoded
+/// stitches.
+pub struct InstructionRow {
+    pub row: u32,
+    pub right_to_left: bool,
+    pub runs: Vec<StitchRun>,
+}
+
+/// Turns a quantized chart into row-by-row written instructions: for
+/// each row of `chart_cell_indices`, consecutive same-colour cells are
+/// collapsed into a single `StitchRun`, and rows alternate direction
+/// (odd rows left-to-right, even rows right-to-left) to match how
+/// intarsia is actually worked back and forth.
+pub fn generate_instructions(image: &DynamicImage, grid_width: u32, grid_height: u32, palette: &[Rgb<u8>]) -> Vec<InstructionRow> {
+    let cells = chart_cell_indices(image, grid_width, grid_height, palette);
+    cells
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut row_cells)| {
+            let row = i as u32 + 1;
+            let right_to_left = row % 2 == 0;
+            if right_to_left {
+                row_cells.reverse();
+            }
+            let mut runs: Vec<StitchRun> = Vec::new();
+            for index in row_cells {
+                match runs.last_mut() {
+                    Some(run) if run.symbol_index == index => run.count += 1,
+                    _ => runs.push(StitchRun {
+                        symbol_index: index,
+                        count: 1,
+                    }),
+                }
+            }
+            InstructionRow {
+                row,
+                right_to_left,
+                runs,
+            }
+        })
+        .collect()
+}
+
+/// Writes human-readable instructions alongside the legend: one line
+/// per row (e.g. `Row 12 (R->L): 4x symbol #0 (#aabbcc), 7x symbol #1
+/// (#112233)`), followed by a legend listing each colour's symbol, hex
+/// value and RGB value (without the stitch counts and totals that
+/// `write_legend_text` adds).
+pub fn write_instructions_text(
+    path: &Path,
+    rows: &[InstructionRow],
+    entries: &[LegendEntry],
+) -> Result<(), Error> {
+    let mut file = File::create(path).map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file, "Intarsia instructions").map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file).map_err(|e| Error::External(e.to_string()))?;
+    for row in rows {
+        let direction = if row.right_to_left { "R->L" } else { "L->R" };
+        let runs: Vec<String> = row
+            .runs
+            .iter()
+            .map(|run| {
+                let channels = entries[run.symbol_index].colour.channels();
+                format!(
+                    "{}x symbol #{} (#{:02x}{:02x}{:02x})",
+                    run.count, run.symbol_index, channels[0], channels[1], channels[2]
+                )
+            })
+            .collect();
+        writeln!(file, "Row {} ({}): {}", row.row, direction, runs.join(", "))
+            .map_err(|e| Error::External(e.to_string()))?;
+    }
+    writeln!(file).map_err(|e| Error::External(e.to_string()))?;
+    writeln!(file, "Legend").map_err(|e| Error::External(e.to_string()))?;
+    for (i, entry) in entries.iter().enumerate() {
+        let channels = entry.colour.channels();
+        writeln!(
+            file,
+            "symbol #{} | #{:02x}{:02x}{:02x} | rgb({}, {}, {})",
+            i, channels[0], channels[1], channels[2], channels[0], channels[1], channels[2]
+        )
+        .map_err(|e| Error::External(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Writes the same instructions as `write_instructions_text`, but as
+/// machine-readable JSON: `{"rows":[{"row":1,"direction":"L->R",
+/// "runs":[{"count":4,"symbol_index":0,"colour":"#aabbcc"}, ...]}, ...],
+/// "legend":[{"symbol_index":0,"colour":"#aabbcc"}, ...]}`.
+pub fn write_instructions_json(path: &Path, rows: &[InstructionRow], entries: &[LegendEntry]) -> Result<(), Error> {
+    let rows_json: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let runs_json: Vec<String> = row
+                .runs
+                .iter()
+                .map(|run| {
+                    let channels = entries[run.symbol_index].colour.channels();
+                    format!(
+                        "{{\"count\":{},\"symbol_index\":{},\"colour\":\"#{:02x}{:02x}{:02x}\"}}",
+                        run.count, run.symbol_index, channels[0], channels[1], channels[2]
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"row\":{},\"direction\":\"{}\",\"runs\":[{}]}}",
+                row.row,
+                if row.right_to_left { "R->L" } else { "L->R" },
+                runs_json.join(",")
+            )
+        })
+        .collect();
+    let legend_json: Vec<String> = entries
         .iter()
-        .map(|x| colour_distance(x, pixel.2))
+        .enumerate()
+        .map(|(i, entry)| {
+            let channels = entry.colour.channels();
+            format!(
+                "{{\"symbol_index\":{},\"colour\":\"#{:02x}{:02x}{:02x}\"}}",
+                i, channels[0], channels[1], channels[2]
+            )
+        })
         .collect();
-    let min_index = min_index(&distances[..]);
-    // let min_index;
-    *pixel.2 = palette[min_index];
+    let json = format!(
+        "{{\"rows\":[{}],\"legend\":[{}]}}",
+        rows_json.join(","),
+        legend_json.join(",")
+    );
+    fs::write(path, json).map_err(|e| Error::External(e.to_string()))?;
+    Ok(())
+}
+
+/// A terminal character cell is roughly twice as tall as it is
+/// wide, so sampling the same number of rows as columns would
+/// stretch the preview vertically; this compensates for it.
+const ASCII_CELL_ASPECT: f32 = 2.0;
+
+/// Renders `image` as a grid of `columns` colored block characters,
+/// each cell sampling the average colour of the pixels underneath
+/// it, painted via an ANSI truecolor background escape. Rows are
+/// derived from `columns` and the image's aspect ratio, corrected
+/// for `ASCII_CELL_ASPECT` so the preview isn't vertically
+/// stretched in a terminal.
+pub fn render_ascii_preview(image: &DynamicImage, columns: u32) -> String {
+    let columns = columns.max(1);
+    let (width, height) = image.dimensions();
+    let rows = ((columns as f32 * height as f32) / (width as f32 * ASCII_CELL_ASPECT))
+        .round()
+        .max(1.0) as u32;
+    let buffer = image.to_rgb8();
+    let cell_width = width as f32 / columns as f32;
+    let cell_height = height as f32 / rows as f32;
+
+    let mut out = String::new();
+    for row in 0..rows {
+        for col in 0..columns {
+            let x0 = (col as f32 * cell_width) as u32;
+            let y0 = (row as f32 * cell_height) as u32;
+            let x1 = (((col + 1) as f32 * cell_width) as u32).max(x0 + 1).min(width);
+            let y1 = (((row + 1) as f32 * cell_height) as u32).max(y0 + 1).min(height);
+            let (mut r, mut g, mut b, mut count) = (0u32, 0u32, 0u32, 0u32);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let pixel = buffer.get_pixel(x, y);
+                    r += pixel[0] as u32;
+                    g += pixel[1] as u32;
+                    b += pixel[2] as u32;
+                    count += 1;
+                }
+            }
+            let count = count.max(1);
+            out.push_str(&format!(
+                "\x1b[48;2;{};{};{}m ",
+                r / count,
+                g / count,
+                b / count
+            ));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// The file format a processed chart can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString)]
+pub enum OutputFormat {
+    #[strum(serialize = "png")]
+    Png,
+    #[strum(serialize = "webp")]
+    WebP,
+    #[strum(serialize = "svg")]
+    Svg,
+}
+
+/// Saves `image` at `path_stub` (extension replaced to match
+/// `format`), returning the path actually written to. PNG and WebP
+/// go through the `image` crate's encoders; SVG is built by hand as
+/// a grid of filled, stroked `<rect>` cells sampled from `image`, so
+/// it scales cleanly for large-format printing.
+pub fn save_chart(
+    image: &DynamicImage,
+    grid_width: u32,
+    grid_height: u32,
+    path_stub: &Path,
+    format: OutputFormat,
+) -> Result<PathBuf, Error> {
+    match format {
+        OutputFormat::Png => {
+            let path = path_stub.with_extension("png");
+            image
+                .save(&path)
+                .map_err(|e| Error::EncodingError(e.to_string()))?;
+            Ok(path)
+        }
+        OutputFormat::WebP => {
+            let path = path_stub.with_extension("webp");
+            image
+                .save_with_format(&path, ImageFormat::WebP)
+                .map_err(|e| Error::EncodingError(e.to_string()))?;
+            Ok(path)
+        }
+        OutputFormat::Svg => {
+            let path = path_stub.with_extension("svg");
+            let svg = render_svg_chart(image, grid_width, grid_height);
+            fs::write(&path, svg).map_err(|e| Error::EncodingError(e.to_string()))?;
+            Ok(path)
+        }
+    }
+}
+
+fn render_svg_chart(image: &DynamicImage, grid_width: u32, grid_height: u32) -> String {
+    let width = image.width();
+    let height = image.height();
+    let cell_width = width as f32 / grid_width as f32;
+    let cell_height = height as f32 / grid_height as f32;
+    let buffer = image.to_rgb8();
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        width, height, width, height
+    );
+    for row in 0..grid_height {
+        for col in 0..grid_width {
+            let cx = (col as f32 * cell_width + cell_width / 2.0).min(width as f32 - 1.0) as u32;
+            let cy = (row as f32 * cell_height + cell_height / 2.0).min(height as f32 - 1.0) as u32;
+            let channels = buffer.get_pixel(cx, cy).channels();
+            svg.push_str(&format!(
+                "  <rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"#{:02x}{:02x}{:02x}\" stroke=\"black\" stroke-width=\"0.5\"/>\n",
+                col as f32 * cell_width,
+                row as f32 * cell_height,
+                cell_width,
+                cell_height,
+                channels[0],
+                channels[1],
+                channels[2],
+            ));
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// One output artifact produced by `Project::transform_image`:
+/// where it was stored, and its pixel dimensions.
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Artifact {
+    pub fn new(path: PathBuf, width: u32, height: u32) -> Artifact {
+        Artifact {
+            path,
+            width,
+            height,
+        }
+    }
+}
+
+/// Every artifact a call to `Project::transform_image` produced,
+/// so callers don't have to guess filenames.
+#[derive(Debug, Clone)]
+pub struct TransformOutputs {
+    pub resized_down: Artifact,
+    pub quantized: Artifact,
+    pub processed: Artifact,
+    pub legend_image: Artifact,
+    pub legend_text: PathBuf,
+    /// The per-stage intermediate artifacts produced by a
+    /// `--pipeline` run, in pipeline order. Empty for projects built
+    /// with the fixed blur/resize/quantize/grid sequence.
+    pub stages: Vec<Artifact>,
+    /// The colour palette used to quantize `processed`.
+    pub palette: Vec<Rgb<u8>>,
+    /// Additional encodings of `processed` requested via a
+    /// comma-separated `--format`, e.g. `--format png,webp`. Empty
+    /// unless more than one format was requested.
+    pub extra_formats: Vec<Artifact>,
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|candidate| candidate.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// Applies the rotate/flip implied by an EXIF `Orientation` tag
+/// (values 1-8, per the TIFF/EXIF spec) to a decoded image.
+fn apply_exif_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Reads the EXIF `Orientation` tag out of a file, if present.
+/// Returns `1` (no-op) when there is no EXIF data at all, which is
+/// the common case for non-camera sources (e.g. screenshots, PNGs).
+fn read_exif_orientation(path: &Path) -> u32 {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return 1,
+    };
+    let mut reader = BufReader::new(file);
+    let exif_reader = exif::Reader::new();
+    let metadata = match exif_reader.read_from_container(&mut reader) {
+        Ok(metadata) => metadata,
+        Err(_) => return 1,
+    };
+    metadata
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1)
+}
+
+/// Maps a `rawloader` CFA colour index (0 = red, 2 = blue, and
+/// both 1 and 3 = green, since most CFAs carry two green samples
+/// per tile) onto an RGB channel index.
+#[cfg(feature = "raw")]
+fn cfa_rgb_channel(cfa_colour: usize) -> usize {
+    match cfa_colour {
+        0 => 0,
+        2 => 2,
+        _ => 1,
+    }
+}
+
+/// Demosaics a single-channel CFA sample buffer into RGB via
+/// bilinear interpolation: a pixel whose native CFA colour already
+/// matches the target channel uses its own sample, and every other
+/// pixel averages the same-channel samples in its 5x5 neighbourhood.
+/// This doesn't assume any particular CFA tile size or layout (the
+/// colour of each sample comes from `cfa.color_at`), only that the
+/// pattern repeats densely enough for same-channel neighbours to
+/// fall within that radius, which holds for Bayer and similar CFAs.
+#[cfg(feature = "raw")]
+fn demosaic_bilinear(data: &[u16], width: usize, height: usize, cfa: &rawloader::CFA) -> RgbImage {
+    const RADIUS: i64 = 2;
+    let mut buffer = RgbImage::new(width as u32, height as u32);
+    for row in 0..height {
+        for col in 0..width {
+            let mut rgb = [0u8; 3];
+            for channel in 0..3 {
+                if cfa_rgb_channel(cfa.color_at(row, col)) == channel {
+                    rgb[channel] = (data[row * width + col] >> 8) as u8;
+                    continue;
+                }
+                let mut sum = 0u32;
+                let mut count = 0u32;
+                for dr in -RADIUS..=RADIUS {
+                    for dc in -RADIUS..=RADIUS {
+                        let (r, c) = (row as i64 + dr, col as i64 + dc);
+                        if r < 0 || c < 0 || r as usize >= height || c as usize >= width {
+                            continue;
+                        }
+                        let (r, c) = (r as usize, c as usize);
+                        if cfa_rgb_channel(cfa.color_at(r, c)) == channel {
+                            sum += data[r * width + c] as u32;
+                            count += 1;
+                        }
+                    }
+                }
+                rgb[channel] = if count > 0 { ((sum / count) >> 8) as u8 } else { 0 };
+            }
+            buffer.put_pixel(col as u32, row as u32, Rgb(rgb));
+        }
+    }
+    buffer
+}
+
+/// Decodes a camera RAW file (e.g. CR2, NEF, ARW, DNG) into a
+/// `DynamicImage`. `rawloader` only hands back the sensor's raw CFA
+/// (colour filter array) samples, one channel per pixel, so those
+/// are demosaiced into a full RGB buffer via `demosaic_bilinear`
+/// before being handed off.
+#[cfg(feature = "raw")]
+fn decode_raw(path: &Path) -> Result<DynamicImage, Error> {
+    let raw_image = rawloader::decode_file(path)
+        .map_err(|e| Error::UnsupportedFormat(format!("could not decode RAW file: {}", e)))?;
+    let (width, height) = (raw_image.width, raw_image.height);
+    let data = match raw_image.data {
+        rawloader::RawImageData::Integer(data) => data,
+        rawloader::RawImageData::Float(data) => {
+            data.iter().map(|v| (v * 65535.0) as u16).collect()
+        }
+    };
+    let buffer = demosaic_bilinear(&data, width, height, &raw_image.cfa);
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+#[cfg(not(feature = "raw"))]
+fn decode_raw(_path: &Path) -> Result<DynamicImage, Error> {
+    Err(Error::UnsupportedFormat(
+        "camera RAW support requires building intarsia with the `raw` feature".to_string(),
+    ))
+}
+
+/// Decodes a HEIF/HEIC file into a `DynamicImage`, via `libheif-rs`.
+#[cfg(feature = "heif")]
+fn decode_heif(path: &Path) -> Result<DynamicImage, Error> {
+    let ctx = libheif_rs::HeifContext::read_from_file(path.to_str().unwrap())
+        .map_err(|e| Error::UnsupportedFormat(format!("could not open HEIF file: {}", e)))?;
+    let handle = ctx
+        .primary_image_handle()
+        .map_err(|e| Error::UnsupportedFormat(format!("could not read HEIF image: {}", e)))?;
+    let heif_image = handle
+        .decode(libheif_rs::ColorSpace::Rgb(libheif_rs::RgbChroma::Rgb), false)
+        .map_err(|e| Error::UnsupportedFormat(format!("could not decode HEIF image: {}", e)))?;
+    let planes = heif_image.planes();
+    let plane = planes.interleaved.ok_or_else(|| {
+        Error::UnsupportedFormat("HEIF image has no interleaved RGB plane".to_string())
+    })?;
+    // libheif row-pads its planes, so `stride` (bytes per row) can be
+    // wider than `width * 3` (bytes of actual pixel data per row);
+    // copying the whole buffer as-is would skew the image. Copy one
+    // row at a time instead, dropping the padding.
+    let row_bytes = plane.width as usize * 3;
+    let mut buffer = RgbImage::new(plane.width, plane.height);
+    for (row, dest) in buffer.chunks_mut(row_bytes).enumerate() {
+        let start = row * plane.stride as usize;
+        dest.copy_from_slice(&plane.data[start..start + row_bytes]);
+    }
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_path: &Path) -> Result<DynamicImage, Error> {
+    Err(Error::UnsupportedFormat(
+        "HEIF/HEIC support requires building intarsia with the `heif` feature".to_string(),
+    ))
+}
+
+const RAW_EXTENSIONS: &[&str] = &["cr2", "nef", "arw", "dng", "raf", "orf", "rw2", "pef"];
+const HEIF_EXTENSIONS: &[&str] = &["heif", "heic"];
+
+/// Decodes the image at `path`, routing camera RAW and HEIF/HEIC
+/// sources through their dedicated decoders, and correcting for an
+/// EXIF `Orientation` tag (so photos shot on phones, which are often
+/// stored sideways with the rotation recorded in EXIF rather than
+/// applied to the pixels, come out upright).
+pub fn read_image(path: &Path) -> Result<DynamicImage, Error> {
+    if has_extension(path, RAW_EXTENSIONS) {
+        return decode_raw(path);
+    }
+    if has_extension(path, HEIF_EXTENSIONS) {
+        return decode_heif(path);
+    }
+    let image = ImageReader::open(path)
+        .map_err(|e| Error::External(e.to_string()))?
+        .decode()
+        .map_err(|e| match e {
+            image::ImageError::Unsupported(_) => Error::UnsupportedFormat(e.to_string()),
+            _ => Error::External(e.to_string()),
+        })?;
+    let orientation = read_exif_orientation(path);
+    Ok(apply_exif_orientation(image, orientation))
 }